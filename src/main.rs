@@ -1,9 +1,15 @@
+mod metrics;
+mod mime;
 mod plex;
 mod proxy;
+mod repo;
+mod rules;
+mod throttle;
 
-use std::env;
+use std::{env, sync::Arc};
 
 use proxy::make_proxy;
+use repo::RedbRepo;
 use tokio::net::TcpListener;
 
 fn env_var(var: &str) -> String {
@@ -12,17 +18,28 @@ fn env_var(var: &str) -> String {
     val
 }
 
+fn optional_env_var(var: &str) -> Option<String> {
+    let val = env::var(var).ok();
+    log::info!("{}: {}", var, val.as_deref().unwrap_or("(not set)"));
+    val
+}
+
 #[tokio::main]
 async fn main() {
 
     env_logger::init();
     
     let plex_url = env_var("PLEX_URL");
-    let plex_library_path = env_var("PLEX_LIBRARY_PATH");
-    let rclone_url = env_var("RCLONE_URL");
+    let rclone_rules = env_var("RCLONE_RULES");
     let port = env_var("PORT");
+    let state_db_path = env_var("STATE_DB_PATH");
+    let rclone_byte_rate_limit = optional_env_var("RCLONE_BYTE_RATE_LIMIT")
+        .map(|v| v.parse::<u64>().unwrap_or_else(|_| panic!("RCLONE_BYTE_RATE_LIMIT must be a number of bytes/sec")))
+        .map(|v| if v == 0 { panic!("RCLONE_BYTE_RATE_LIMIT must be greater than 0; unset it to disable the limit") } else { v });
+
+    let repo = Arc::new(RedbRepo::open(&state_db_path));
 
-    let proxy = make_proxy(plex_url, plex_library_path, rclone_url);
+    let proxy = make_proxy(plex_url, rclone_rules, repo, rclone_byte_rate_limit);
 
     let addr = format!("0.0.0.0:{}", port);
     let listener = TcpListener::bind(addr).await.unwrap();
@@ -0,0 +1,50 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, Encoder, HistogramVec,
+    IntCounter, IntCounterVec, TextEncoder,
+};
+
+/// Requests served by each backend, so operators can confirm offload to rclone is happening.
+pub static REQUESTS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proxy_requests_total",
+        "Total proxied requests by backend",
+        &["backend"]
+    )
+    .unwrap()
+});
+
+/// Requests that couldn't be routed to rclone because the token or media file wasn't known yet.
+pub static RCLONE_CACHE_MISS_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "proxy_rclone_cache_miss_total",
+        "Requests that fell back to Plex because the token or media file was unknown"
+    )
+    .unwrap()
+});
+
+pub static METADATA_PARSED_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "proxy_metadata_parsed_total",
+        "Plex responses successfully parsed as a Container for media capture"
+    )
+    .unwrap()
+});
+
+/// Latency of the upstream `client().send()` call, by backend.
+pub static UPSTREAM_LATENCY_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "proxy_upstream_latency_seconds",
+        "Latency of upstream requests by backend",
+        &["backend"]
+    )
+    .unwrap()
+});
+
+/// Renders all registered metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let metric_families = prometheus::gather();
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&metric_families, &mut buffer).unwrap();
+    String::from_utf8(buffer).unwrap()
+}
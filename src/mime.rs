@@ -0,0 +1,43 @@
+/// Guesses a media content type from a file's extension, for rclone backends that serve
+/// everything as `application/octet-stream`.
+pub fn guess_from_path(file: &str) -> Option<&'static str> {
+    let ext = file.rsplit('.').next()?.to_lowercase();
+    Some(match ext.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "avi" => "video/x-msvideo",
+        "mov" => "video/quicktime",
+        "webm" => "video/webm",
+        "ts" => "video/mp2t",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "aac" => "audio/aac",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "srt" => "application/x-subrip",
+        "vtt" => "text/vtt",
+        "ass" | "ssa" => "text/x-ssa",
+        "sub" => "text/plain",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guesses_known_extensions_case_insensitively() {
+        assert_eq!(guess_from_path("/data/movies/Foo.mkv"), Some("video/x-matroska"));
+        assert_eq!(guess_from_path("/data/movies/Foo.MP4"), Some("video/mp4"));
+        assert_eq!(guess_from_path("/data/subs/Foo.srt"), Some("application/x-subrip"));
+    }
+
+    #[test]
+    fn returns_none_for_unknown_or_missing_extension() {
+        assert_eq!(guess_from_path("/data/movies/Foo.xyz"), None);
+        assert_eq!(guess_from_path("/data/movies/Foo"), None);
+    }
+}
@@ -1,37 +1,45 @@
 use std::{collections::HashMap, sync::Arc, time::Instant};
 
-use axum::{body::{self, Body}, extract::{Request, State}, http::{response, HeaderMap, Response}, routing::get, Router};
+use axum::{body::{self, Body}, extract::{Request, State}, http::{header::{CONTENT_LENGTH, CONTENT_TYPE, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, RANGE}, response, HeaderMap, HeaderValue, Method, Response}, middleware::{self, Next}, routing::get, Router};
 use reqwest::{StatusCode, Url};
 use tokio::sync::RwLock;
 
-use crate::plex::Container;
+use crate::{metrics, mime, plex::Container, repo::Repo, rules::{match_rule, parse_rclone_rules, RcloneRule}, throttle};
 
 const TOKEN_TTL_MINUTES: u64 = 15;
 
+/// How long a token's stored timestamp is allowed to go stale before we bother refreshing
+/// it, so routine browse/thumbnail traffic doesn't turn into a disk write on every request.
+const TOKEN_REFRESH_SECONDS: u64 = 60;
+
+/// Cap on how much of a response body we'll buffer to look for media parts,
+/// so we never load a whole movie into memory just to find out it isn't JSON.
+const METADATA_MAX_BYTES: usize = 1_048_576;
+
 #[derive(Clone)]
 struct ProxyState {
-    seen_tokens: Arc<RwLock<HashMap<String, Instant>>>,
+    repo: Arc<dyn Repo>,
     media_map: Arc<RwLock<HashMap<String, String>>>,
     plex_url: Url,
-    plex_library_path: String,
-    rclone_url: String,
+    rclone_rules: Vec<RcloneRule>,
+    rclone_byte_rate_limit: Option<u64>,
 }
 
 impl ProxyState {
-    async fn add_token(&self, token: String) {
-        let mut seen_tokens = self.seen_tokens.write().await;
-        seen_tokens.insert(token, Instant::now());
-
-        let stale_tokens = seen_tokens.iter()
-            .filter(|(_, v)| v.elapsed().as_secs() > TOKEN_TTL_MINUTES * 60)
-            .map(|(k, _)| k.clone())
-            .collect::<Vec<_>>();
-        for token in stale_tokens {
-            seen_tokens.remove(&token);
+    fn add_token(&self, token: &str) {
+        // a fresh-enough timestamp is still good, skip the write-transaction and commit
+        if !self.repo.token_seen(token, TOKEN_REFRESH_SECONDS) {
+            self.repo.add_token(token, TOKEN_TTL_MINUTES * 60);
         }
     }
 
+    fn token_seen(&self, token: &str) -> bool {
+        self.repo.token_seen(token, TOKEN_TTL_MINUTES * 60)
+    }
+
     async fn add_media(&self, key: String, file: String) {
+        self.repo.add_media(&key, &file);
+
         let mut media_map = self.media_map.write().await;
         media_map.insert(key, file);
     }
@@ -61,6 +69,7 @@ async fn pass_to_plex(State(ProxyState { plex_url, .. }): State<ProxyState>, req
     url.set_path(request.uri().path());
     url.set_query(request.uri().query());
 
+    let started_at = Instant::now();
     let plex_resp = client()
         .request(request.method().clone(), url)
         .headers(request.headers().clone())
@@ -68,6 +77,8 @@ async fn pass_to_plex(State(ProxyState { plex_url, .. }): State<ProxyState>, req
         .send()
         .await
         .map_err(|_| (StatusCode::BAD_GATEWAY, "Failed to proxy request"))?;
+    metrics::UPSTREAM_LATENCY_SECONDS.with_label_values(&["plex"]).observe(started_at.elapsed().as_secs_f64());
+    metrics::REQUESTS_TOTAL.with_label_values(&["plex"]).inc();
 
     let response = Response::builder()
         .status(plex_resp.status())
@@ -78,19 +89,55 @@ async fn pass_to_plex(State(ProxyState { plex_url, .. }): State<ProxyState>, req
     Ok(response)
 }
 
-async fn pass_to_rclone(rclone_url: &str) -> Result<Response<Body>, (StatusCode, &'static str)> {
+async fn pass_to_rclone(rclone_url: &str, file: &str, method: &Method, headers: &HeaderMap, byte_rate_limit: Option<u64>) -> Result<Response<Body>, (StatusCode, &'static str)> {
     log::info!("Proxying to rclone: {}", rclone_url);
 
+    // forward the range/conditional headers so seeking and resume-from-position work
+    let mut forward_headers = HeaderMap::new();
+    for name in [RANGE, IF_RANGE, IF_MODIFIED_SINCE, IF_NONE_MATCH] {
+        if let Some(value) = headers.get(&name) {
+            forward_headers.insert(name, value.clone());
+        }
+    }
+
+    let started_at = Instant::now();
     let rclone_resp = client()
-        .get(rclone_url)
+        .request(method.clone(), rclone_url)
+        .headers(forward_headers)
         .send()
         .await
         .map_err(|_| (StatusCode::BAD_GATEWAY, "Failed to proxy request"))?;
+    metrics::UPSTREAM_LATENCY_SECONDS.with_label_values(&["rclone"]).observe(started_at.elapsed().as_secs_f64());
+    metrics::REQUESTS_TOTAL.with_label_values(&["rclone"]).inc();
+
+    let status = rclone_resp.status();
+    let mut headers = rclone_resp.headers().clone();
+
+    // rclone often can't tell us the type, so fall back to guessing it from the file extension
+    let has_specific_type = headers.get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| !v.eq_ignore_ascii_case("application/octet-stream"));
+    if !has_specific_type {
+        if let Some(mime_type) = mime::guess_from_path(file) {
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(mime_type));
+        }
+    }
+
+    // a HEAD response carries no body, but status and headers (Content-Length etc.) still matter
+    let body = if *method == Method::HEAD {
+        Body::empty()
+    } else {
+        match byte_rate_limit {
+            // burst capacity of one second's worth of traffic
+            Some(rate) => Body::from_stream(throttle::throttle(rclone_resp.bytes_stream(), rate, rate)),
+            None => Body::from_stream(rclone_resp.bytes_stream()),
+        }
+    };
 
     let response = Response::builder()
-        .status(rclone_resp.status())
-        .headers(rclone_resp.headers().clone())
-        .body(Body::from_stream(rclone_resp.bytes_stream()))
+        .status(status)
+        .headers(headers)
+        .body(body)
         .unwrap();
 
     Ok(response)
@@ -102,84 +149,127 @@ async fn fallback(state: State<ProxyState>, request: Request) -> Result<Response
 
     // only try to proxy if there's a token that we know about
     if let Some(token) = request.headers().get("x-plex-token").and_then(|v| v.to_str().ok()) {
-        let seen_tokens = state.seen_tokens.read().await;
-        if seen_tokens.contains_key(token) {
+        if state.token_seen(token) {
             // and it's for a known media file
             if let Some(path) = request.uri().path_and_query().map(|pq| pq.path()) {
                 let media_map = state.media_map.read().await;
                 if let Some(file) = media_map.get(path) {
-                    if let Some(path) = file.strip_prefix(&state.plex_library_path) {
-                        let rclone_url = state.rclone_url.trim_end_matches('/');
-                        let path = path.trim_start_matches('/');
-                        let url = format!("{}/{}", rclone_url, path);
-                        return pass_to_rclone(&url).await;
+                    if let Some(rule) = match_rule(&state.rclone_rules, file) {
+                        log::info!("Matched rule '{}' for file: {}", rule.library_prefix, file);
+
+                        let relative = file.strip_prefix(&rule.library_prefix).unwrap().trim_start_matches('/');
+                        let rclone_base = rule.rclone_url.trim_end_matches('/');
+                        let url = format!("{}/{}", rclone_base, relative);
+                        return pass_to_rclone(&url, file, request.method(), request.headers(), state.rclone_byte_rate_limit).await;
                     } else {
                         log::info!("Not proxying unknown media file: {}", file);
+                        metrics::RCLONE_CACHE_MISS_TOTAL.inc();
                     }
+                } else {
+                    metrics::RCLONE_CACHE_MISS_TOTAL.inc();
                 }
             }
+        } else {
+            metrics::RCLONE_CACHE_MISS_TOTAL.inc();
         }
     }
 
     pass_to_plex(state.clone(), request).await
 }
 
-async fn capture_metadata(state: State<ProxyState>, headers: HeaderMap, request: Request<Body>) -> Result<Response<Body>, (StatusCode, &'static str)> {
-    let response = pass_to_plex(state.clone(), request).await?;
+/// Harvests `Part.key`/`Part.file` pairs out of any Plex response that turns out to be a
+/// `Container`, regardless of which route produced it (children, `/all`, search, On Deck, a
+/// direct metadata fetch, ...). Runs as a layer over the whole router so it sees everything.
+async fn capture_metadata(State(state): State<ProxyState>, request: Request, next: Next) -> Response<Body> {
+    let plex_token = request.headers().get("x-plex-token").and_then(|v| v.to_str().ok().map(|s| s.to_string()));
+
+    let response = next.run(request).await;
 
-    if matches!(response.status(), StatusCode::OK) {
-        let plex_token = headers.get("x-plex-token").and_then(|v| v.to_str().ok().map(|s| s.to_string()));
-        if let Some(plex_token) = plex_token {
-            state.add_token(plex_token).await;
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+
+    if let Some(token) = &plex_token {
+        state.add_token(token);
+    }
+
+    let is_json = response.headers().get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("json"));
+    if !is_json {
+        return response;
+    }
+
+    // Content-Length is a fast-path skip only: pass_to_plex's gzip-decoding client strips it
+    // (along with Content-Encoding) on the common case of a gzipped Plex JSON response, and
+    // chunked responses never had one, so its absence must not skip parsing. `to_bytes`'s own
+    // cap below is what actually guards against buffering an oversized body.
+    let oversized = response.headers().get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok())
+        .is_some_and(|len| len > METADATA_MAX_BYTES);
+    if oversized {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let data = match body::to_bytes(body, METADATA_MAX_BYTES).await {
+        Ok(data) => data,
+        Err(e) => {
+            log::warn!("Failed to read response body for metadata capture: {}", e);
+            return Response::from_parts(parts, Body::empty());
         }
+    };
 
-        // take the whole body, we need it for parsing anyway
-        let status = response.status();
-        let headers = response.headers().clone();
-        let data = body::to_bytes(response.into_body(), 1_048_576).await
-            .map_err(|_| (StatusCode::BAD_GATEWAY, "Failed to read response body"))?;
+    match serde_json::from_slice::<Container>(&data) {
+        Ok(container) => {
+            metrics::METADATA_PARSED_TOTAL.inc();
 
-        match serde_json::from_slice::<Container>(&data) {
-            Ok(container) => {
-                let parts = container.media_container.metadata.into_iter()
+            let parts_iter = container.media_container.metadata.into_iter()
                 .flat_map(|md| md.media.into_iter()
                     .flat_map(|media| media.parts.into_iter()
                         .map(|p| (p.key, p.file))));
-                for (key, file) in parts {
-                    state.add_media(key, file).await;
-                }
-            },
-            Err(e) => {
-                log::warn!("Failed to parse metadata: {}", e);
+            for (key, file) in parts_iter {
+                state.add_media(key, file).await;
             }
+        },
+        Err(e) => {
+            log::warn!("Response didn't deserialize as a Container, skipping: {}", e);
         }
-
-        let response = Response::builder()
-            .status(status)
-            .headers(headers)
-            .body(Body::from(data))
-            .unwrap();
-        
-        return Ok(response);
     }
 
-    Ok(response)
+    Response::from_parts(parts, Body::from(data))
+}
+
+async fn metrics_handler() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(metrics::render()))
+        .unwrap()
 }
 
-pub fn make_proxy(plex_url: String, plex_library_path: String, rclone_url: String) -> Router {
-    
+pub fn make_proxy(plex_url: String, rclone_rules: String, repo: Arc<dyn Repo>, rclone_byte_rate_limit: Option<u64>) -> Router {
+
     let plex_url: Url = plex_url.parse().unwrap();
+    let rclone_rules = parse_rclone_rules(&rclone_rules);
+
+    // hydrate the in-memory map from disk so direct-to-rclone routing survives a restart
+    let media_map = repo.all_media().into_iter().collect::<HashMap<_, _>>();
+    log::info!("Hydrated {} media entries from the repo", media_map.len());
 
     let state = ProxyState {
-        seen_tokens: Arc::new(RwLock::new(HashMap::new())),
-        media_map: Arc::new(RwLock::new(HashMap::new())),
+        repo,
+        media_map: Arc::new(RwLock::new(media_map)),
         plex_url,
-        plex_library_path,
-        rclone_url,
+        rclone_rules,
+        rclone_byte_rate_limit,
     };
 
     Router::new()
-        .route("/library/metadata/:id/children", get(capture_metadata))
+        // gated ahead of the fallback so it's never proxied through to Plex
+        .route("/metrics", get(metrics_handler))
         .fallback(fallback)
+        .layer(middleware::from_fn_with_state(state.clone(), capture_metadata))
         .with_state(state)
 }
@@ -0,0 +1,96 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use redb::{Database, ReadableTable, TableDefinition};
+
+const MEDIA_TABLE: TableDefinition<&str, &str> = TableDefinition::new("media");
+const TOKEN_TABLE: TableDefinition<&str, u64> = TableDefinition::new("tokens");
+
+/// Persistent key/value backend for the media map and token cache, so a proxy
+/// restart doesn't force every client to re-browse their libraries.
+pub trait Repo: Send + Sync {
+    fn get_media(&self, key: &str) -> Option<String>;
+    fn add_media(&self, key: &str, file: &str);
+    fn add_token(&self, token: &str, ttl_secs: u64);
+    fn token_seen(&self, token: &str, ttl_secs: u64) -> bool;
+    fn all_media(&self) -> Vec<(String, String)>;
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+pub struct RedbRepo {
+    db: Database,
+}
+
+impl RedbRepo {
+    pub fn open(path: &str) -> Self {
+        let db = Database::create(path)
+            .unwrap_or_else(|e| panic!("failed to open repo database at {}: {}", path, e));
+
+        // make sure both tables exist before anything tries to read them
+        let txn = db.begin_write().unwrap();
+        txn.open_table(MEDIA_TABLE).unwrap();
+        txn.open_table(TOKEN_TABLE).unwrap();
+        txn.commit().unwrap();
+
+        Self { db }
+    }
+}
+
+impl Repo for RedbRepo {
+    fn get_media(&self, key: &str) -> Option<String> {
+        let txn = self.db.begin_read().ok()?;
+        let table = txn.open_table(MEDIA_TABLE).ok()?;
+        table.get(key).ok().flatten().map(|v| v.value().to_string())
+    }
+
+    fn add_media(&self, key: &str, file: &str) {
+        let txn = self.db.begin_write().unwrap();
+        {
+            let mut table = txn.open_table(MEDIA_TABLE).unwrap();
+            table.insert(key, file).unwrap();
+        }
+        txn.commit().unwrap();
+    }
+
+    fn add_token(&self, token: &str, ttl_secs: u64) {
+        let now = now_secs();
+        let txn = self.db.begin_write().unwrap();
+        {
+            let mut table = txn.open_table(TOKEN_TABLE).unwrap();
+            table.insert(token, now).unwrap();
+
+            // sweep anything that's aged out so the table doesn't grow without bound
+            let stale_tokens = table.iter().unwrap()
+                .filter_map(|entry| entry.ok())
+                .filter(|(_, seen_at)| now.saturating_sub(seen_at.value()) > ttl_secs)
+                .map(|(k, _)| k.value().to_string())
+                .collect::<Vec<_>>();
+            for stale_token in stale_tokens {
+                table.remove(stale_token.as_str()).unwrap();
+            }
+        }
+        txn.commit().unwrap();
+    }
+
+    fn token_seen(&self, token: &str, ttl_secs: u64) -> bool {
+        let Ok(txn) = self.db.begin_read() else { return false };
+        let Ok(table) = txn.open_table(TOKEN_TABLE) else { return false };
+        match table.get(token) {
+            Ok(Some(seen_at)) => now_secs().saturating_sub(seen_at.value()) <= ttl_secs,
+            _ => false,
+        }
+    }
+
+    fn all_media(&self) -> Vec<(String, String)> {
+        let txn = self.db.begin_read().unwrap();
+        let table = txn.open_table(MEDIA_TABLE).unwrap();
+        table
+            .iter()
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .map(|(k, v)| (k.value().to_string(), v.value().to_string()))
+            .collect()
+    }
+}
@@ -0,0 +1,98 @@
+use serde::Deserialize;
+
+/// Maps a library path prefix to the rclone remote that serves it, so libraries on
+/// different cloud remotes can each be routed to the right place.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RcloneRule {
+    pub library_prefix: String,
+    pub rclone_url: String,
+}
+
+/// Parses `RCLONE_RULES` as either a JSON array of `{"library_prefix", "rclone_url"}`
+/// objects, or a `;`-separated list of `prefix=url` pairs for simple setups.
+pub fn parse_rclone_rules(raw: &str) -> Vec<RcloneRule> {
+    let raw = raw.trim();
+
+    if raw.starts_with('[') {
+        return serde_json::from_str(raw)
+            .unwrap_or_else(|e| panic!("RCLONE_RULES is not valid JSON: {}", e));
+    }
+
+    raw.split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (prefix, url) = entry.split_once('=')
+                .unwrap_or_else(|| panic!("RCLONE_RULES entry '{}' is not of the form prefix=url", entry));
+            RcloneRule {
+                library_prefix: prefix.trim().to_string(),
+                rclone_url: url.trim().to_string(),
+            }
+        })
+        .collect()
+}
+
+/// True if `prefix` matches `file` on a path boundary - either an exact match, or followed
+/// by a `/` - so `/data/movies` matches `/data/movies/Foo.mkv` but not a sibling library like
+/// `/data/movies-4k/Foo.mkv`.
+fn prefix_matches(file: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    file == prefix || file.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Picks the rule whose prefix matches the longest leading portion of `file`, so a more
+/// specific rule (e.g. `/data/tv/anime`) wins over a broader one (e.g. `/data/tv`).
+pub fn match_rule<'a>(rules: &'a [RcloneRule], file: &str) -> Option<&'a RcloneRule> {
+    rules.iter()
+        .filter(|rule| prefix_matches(file, &rule.library_prefix))
+        .max_by_key(|rule| rule.library_prefix.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(prefix: &str, url: &str) -> RcloneRule {
+        RcloneRule { library_prefix: prefix.to_string(), rclone_url: url.to_string() }
+    }
+
+    #[test]
+    fn prefix_matches_respects_path_boundaries() {
+        assert!(prefix_matches("/data/movies/Foo.mkv", "/data/movies"));
+        assert!(prefix_matches("/data/movies", "/data/movies"));
+        assert!(!prefix_matches("/data/movies-4k/Foo.mkv", "/data/movies"));
+        assert!(!prefix_matches("/data/moviesHD/Foo.mkv", "/data/movies"));
+    }
+
+    #[test]
+    fn match_rule_picks_the_longest_matching_prefix() {
+        let rules = vec![
+            rule("/data/tv", "http://remote-a"),
+            rule("/data/tv/anime", "http://remote-b"),
+        ];
+
+        let matched = match_rule(&rules, "/data/tv/anime/Show/ep1.mkv").unwrap();
+        assert_eq!(matched.rclone_url, "http://remote-b");
+
+        let matched = match_rule(&rules, "/data/tv/Drama/ep1.mkv").unwrap();
+        assert_eq!(matched.rclone_url, "http://remote-a");
+    }
+
+    #[test]
+    fn match_rule_returns_none_when_nothing_matches() {
+        let rules = vec![rule("/data/movies", "http://remote-a")];
+        assert!(match_rule(&rules, "/data/tv/Show/ep1.mkv").is_none());
+    }
+
+    #[test]
+    fn parse_rclone_rules_supports_delimited_and_json() {
+        let delimited = parse_rclone_rules("/data/movies=http://a;/data/tv=http://b");
+        assert_eq!(delimited.len(), 2);
+        assert_eq!(delimited[0].library_prefix, "/data/movies");
+        assert_eq!(delimited[1].rclone_url, "http://b");
+
+        let json = parse_rclone_rules(r#"[{"library_prefix":"/data/movies","rclone_url":"http://a"}]"#);
+        assert_eq!(json.len(), 1);
+        assert_eq!(json[0].rclone_url, "http://a");
+    }
+}